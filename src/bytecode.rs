@@ -0,0 +1,629 @@
+//! Compile Nickel terms into a byte-addressed instruction stream, and run that stream.
+//!
+//! [crate::stack] tracks the continuations still owed to the abstract machine (pending
+//! arguments, thunk updates, operator continuations, outstanding equalities). This module
+//! describes the *code* the machine runs to produce those continuations: instead of
+//! re-dispatching on [Term] structure at every evaluation step, a [Term] is lowered once, ahead
+//! of time, into a [Chunk] - a flat sequence of one-byte [Op]s with their operands written
+//! inline - and [Chunk::run] then simply walks an instruction pointer over it, using a plain
+//! `Vec<Closure>` as the operand stack the opcodes push and pop.
+//!
+//! `run` only knows how to decode and sequence opcodes; it has no idea how to force a closure to
+//! weak head normal form or how to carry out a primitive operation; those remain the job of the
+//! existing term-walking evaluator and `crate::operation`, respectively, and are handed in as
+//! callbacks. `Compiler`, in turn, only lowers the handful of term shapes that have a direct,
+//! context-free bytecode encoding (applications, primops, conditionals, equality tests, and
+//! literals); anything else - `Var`, `Let`, `Fun`, records, and so on, all of which need an
+//! environment to make sense of - is compiled as a `PushConst` of the term itself, to be forced
+//! by the evaluator the same way it always was. `Term::App`'s argument is always compiled this
+//! way too, regardless of its own shape: compiling it with the same direct-encoding rules as a
+//! top-level term would run it to completion before `Apply` even executes, forcing it even if
+//! the applied function never touches it. Pushing it as an opaque constant instead keeps it a
+//! thunk, preserving the same "thunk a subterm, force it on demand" call-by-need discipline the
+//! rest of the lazy evaluator already relies on.
+use crate::eval::Closure;
+use crate::position::RawSpan;
+use crate::stack::{RecursionLimitExceeded, Stack};
+use crate::term::{BinaryOp, Term, UnaryOp};
+use crate::varint;
+use std::convert::TryFrom;
+
+/// A single bytecode instruction.
+///
+/// `Op` is `#[repr(u8)]` so that every instruction occupies exactly one byte in a [Chunk]'s
+/// `code` stream. Operands - constant pool indices, argument counts, jump targets - are not
+/// carried as enum payloads; they are written as trailing bytes immediately following the
+/// opcode and decoded by the instruction pointer as it steps through `code`.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Push `constants[u16]` onto the operand stack.
+    PushConst,
+    /// Pop the function off the top of the operand stack and then the `u16` operand's count of
+    /// arguments beneath it, push those arguments onto [Stack], and force the function.
+    Apply,
+    /// Update the thunk owning the current call frame with the value on top of the operand
+    /// stack, without popping it.
+    UpdateThunk,
+    /// Apply the unary primitive operation at `unary_ops[u16]` to the value on top of the stack.
+    Op1,
+    /// Apply the binary primitive operation at `binary_ops[u16]` to the top two values on the
+    /// stack.
+    Op2,
+    /// Pop the top two values off the stack and test them for structural equality.
+    TestEq,
+    /// Discard every outstanding equality still queued on [Stack] for the comparison that just
+    /// ran. Emitted right after every `TestEq`; a no-op when nothing is queued, so it is safe to
+    /// run unconditionally rather than only on the failing branch.
+    PopEqs,
+    /// Unconditionally jump to the absolute `code` offset encoded in the following `u32`.
+    Jump,
+    /// Pop a boolean off the operand stack and jump to the absolute `u32` offset if it is
+    /// `false`.
+    JumpIfFalse,
+    /// End the chunk, leaving its result as the sole value on the operand stack.
+    Return,
+}
+
+impl Op {
+    /// The number of operand bytes that follow this opcode in a `code` stream.
+    pub fn operand_len(self) -> usize {
+        match self {
+            Op::PushConst => 2,
+            Op::Apply => 2,
+            Op::UpdateThunk => 0,
+            Op::Op1 => 2,
+            Op::Op2 => 2,
+            Op::TestEq => 0,
+            Op::PopEqs => 0,
+            Op::Jump | Op::JumpIfFalse => 4,
+            Op::Return => 0,
+        }
+    }
+}
+
+/// A compiled, directly executable program.
+///
+/// Produced once per term by [Compiler::compile] and then run by [Chunk::run]: an instruction
+/// pointer walks `code`, decoding one [Op] and its trailing operand bytes at a time.
+#[derive(Debug, Clone, Default)]
+pub struct Chunk {
+    /// The instruction stream: one opcode byte followed by `Op::operand_len` operand bytes.
+    pub code: Vec<u8>,
+    /// The constant pool referenced by `PushConst` operands.
+    pub constants: Vec<Term>,
+    /// The unary operator pool referenced by `Op1` operands.
+    pub unary_ops: Vec<UnaryOp>,
+    /// The binary operator pool referenced by `Op2` operands.
+    pub binary_ops: Vec<BinaryOp>,
+    /// Source spans for instructions that can raise a run-time error, as `(code offset, span)`
+    /// pairs - sparse, since only a handful of opcodes (currently just `Apply`) need one, rather
+    /// than one entry per instruction.
+    pub spans: Vec<(usize, RawSpan)>,
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Chunk::default()
+    }
+
+    /// Append an opcode to the instruction stream and return its offset.
+    pub fn push_op(&mut self, op: Op) -> usize {
+        let offset = self.code.len();
+        self.code.push(op as u8);
+        offset
+    }
+
+    /// Append a little-endian `u16` operand.
+    pub fn push_u16(&mut self, operand: u16) {
+        self.code.extend_from_slice(&operand.to_le_bytes());
+    }
+
+    /// Append a little-endian `u32` operand, used for jump targets.
+    pub fn push_u32(&mut self, operand: u32) {
+        self.code.extend_from_slice(&operand.to_le_bytes());
+    }
+
+    /// Append `term` to the constant pool and return its index. Equal terms are not shared.
+    pub fn add_constant(&mut self, term: Term) -> u16 {
+        self.constants.push(term);
+        (self.constants.len() - 1) as u16
+    }
+
+    /// Append `op` to the unary operator pool and return its index.
+    pub fn add_unary_op(&mut self, op: UnaryOp) -> u16 {
+        self.unary_ops.push(op);
+        (self.unary_ops.len() - 1) as u16
+    }
+
+    /// Append `op` to the binary operator pool and return its index.
+    pub fn add_binary_op(&mut self, op: BinaryOp) -> u16 {
+        self.binary_ops.push(op);
+        (self.binary_ops.len() - 1) as u16
+    }
+
+    /// Patch a previously emitted `u32` jump target once its real destination is known, for
+    /// forward jumps whose destination isn't compiled yet at the point the jump is emitted.
+    pub fn patch_jump(&mut self, at: usize, target: u32) {
+        self.code[at..at + 4].copy_from_slice(&target.to_le_bytes());
+    }
+
+    /// Re-encode `code`'s fixed-width operands as [varint]s, for compact on-disk storage: the
+    /// fixed `u16`/`u32` widths this chunk runs with are chosen so the interpreter can decode an
+    /// operand without branching, but most operands - pool indices, short jumps - are small
+    /// enough to fit in a single varint byte, so a cache file written this way is considerably
+    /// smaller than a raw dump of `code`.
+    pub fn encode_code(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut cursor = self.code.as_slice();
+        while let Some((&opcode, rest)) = cursor.split_first() {
+            out.push(opcode);
+            // `code` is this module's own output, never untrusted bytes, so an unrecognized
+            // opcode here would mean this module miscompiled its own chunk.
+            let op = decode_op(opcode)
+                .expect("Chunk::code only ever contains opcodes this module wrote");
+            let len = op.operand_len();
+            let (operand, rest) = rest.split_at(len);
+            cursor = rest;
+            match len {
+                0 => {}
+                2 => varint::write(
+                    &mut out,
+                    u16::from_le_bytes([operand[0], operand[1]]) as u64,
+                ),
+                4 => varint::write(
+                    &mut out,
+                    u32::from_le_bytes([operand[0], operand[1], operand[2], operand[3]]) as u64,
+                ),
+                _ => unreachable!("no opcode has an operand of this width"),
+            }
+        }
+        out
+    }
+
+    /// Inverse of [Chunk::encode_code]: rebuild a fixed-width `code` stream from its varint
+    /// encoding. Returns `None` if `encoded` is malformed - including, since `encoded` may come
+    /// straight off disk via [crate::cache], if it contains a byte that isn't a valid opcode.
+    pub fn decode_code(encoded: &[u8]) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut cursor = encoded;
+        while let Some((&opcode, rest)) = cursor.split_first() {
+            out.push(opcode);
+            cursor = rest;
+            let op = decode_op(opcode)?;
+            match op.operand_len() {
+                0 => {}
+                2 => out.extend_from_slice(
+                    &u16::try_from(varint::read(&mut cursor)?).ok()?.to_le_bytes(),
+                ),
+                4 => out.extend_from_slice(
+                    &u32::try_from(varint::read(&mut cursor)?).ok()?.to_le_bytes(),
+                ),
+                _ => unreachable!("no opcode has an operand of this width"),
+            }
+        }
+        Some(out)
+    }
+
+    /// Run this chunk to completion, starting from an empty operand stack.
+    ///
+    /// `force` reduces a [Closure] to weak head normal form using the existing, term-walking
+    /// evaluator; `apply_unary`/`apply_binary` hand a primop and its already-forced operands to
+    /// the existing primop implementations in `crate::operation`. Keeping those three behind
+    /// caller-supplied callbacks lets this loop stay a pure walk over `code` - decode, dispatch,
+    /// operand-stack bookkeeping - without hard-wiring it to the evaluator's or the operation
+    /// module's exact signatures; argument, thunk and equality bookkeeping still goes through the
+    /// shared `stack`, exactly as it did before this chunk existed, so recursion-limit accounting
+    /// and thunk updates keep working unchanged.
+    pub fn run(
+        &self,
+        stack: &mut Stack,
+        mut force: impl FnMut(Closure, &mut Stack) -> Closure,
+        mut apply_unary: impl FnMut(&UnaryOp, Closure, &mut Stack) -> Closure,
+        mut apply_binary: impl FnMut(&BinaryOp, Closure, Closure, &mut Stack) -> Closure,
+    ) -> Result<Closure, RecursionLimitExceeded> {
+        let mut ip = 0usize;
+        let mut operands: Vec<Closure> = Vec::new();
+
+        loop {
+            let instr_at = ip;
+            let op = decode_op(self.code[ip])
+                .expect("Chunk::code only ever contains opcodes this module wrote");
+            let operand_start = ip + 1;
+            ip = operand_start + op.operand_len();
+
+            match op {
+                Op::PushConst => {
+                    let idx = read_u16(&self.code, operand_start);
+                    operands.push(Closure::atomic_closure(
+                        self.constants[idx as usize].clone().into(),
+                    ));
+                }
+                Op::Apply => {
+                    let argc = read_u16(&self.code, operand_start) as usize;
+                    let func = operands
+                        .pop()
+                        .expect("Apply with no function on the operand stack");
+                    let mut args = Vec::with_capacity(argc);
+                    for _ in 0..argc {
+                        args.push(
+                            operands
+                                .pop()
+                                .expect("Apply with fewer arguments than its operand count"),
+                        );
+                    }
+                    let span = self
+                        .spans
+                        .iter()
+                        .find(|(at, _)| *at == instr_at)
+                        .map(|(_, span)| span.clone());
+                    for arg in args.into_iter().rev() {
+                        stack.push_arg(arg, span.clone())?;
+                    }
+                    operands.push(force(func, stack));
+                }
+                Op::UpdateThunk => {
+                    if let Some(thunk) = stack.pop_thunk() {
+                        if let Some(cell) = thunk.upgrade() {
+                            let value = operands
+                                .last()
+                                .expect("UpdateThunk with empty operand stack")
+                                .clone();
+                            *cell.borrow_mut() = value;
+                        }
+                    }
+                }
+                Op::Op1 => {
+                    let idx = read_u16(&self.code, operand_start);
+                    let arg = operands.pop().expect("Op1 with empty operand stack");
+                    let arg = force(arg, stack);
+                    operands.push(apply_unary(&self.unary_ops[idx as usize], arg, stack));
+                }
+                Op::Op2 => {
+                    let idx = read_u16(&self.code, operand_start);
+                    let right = operands.pop().expect("Op2 with fewer than two operands");
+                    let left = operands.pop().expect("Op2 with fewer than two operands");
+                    let left = force(left, stack);
+                    let right = force(right, stack);
+                    operands.push(apply_binary(
+                        &self.binary_ops[idx as usize],
+                        left,
+                        right,
+                        stack,
+                    ));
+                }
+                Op::TestEq => {
+                    let right = operands.pop().expect("TestEq with fewer than two operands");
+                    let left = operands.pop().expect("TestEq with fewer than two operands");
+                    let left = force(left, stack);
+                    let right = force(right, stack);
+                    operands.push(Closure::atomic_closure(Term::Bool(left == right).into()));
+                }
+                Op::PopEqs => stack.clear_eqs(),
+                Op::Jump => {
+                    ip = read_u32(&self.code, operand_start) as usize;
+                }
+                Op::JumpIfFalse => {
+                    let cond = operands
+                        .pop()
+                        .expect("JumpIfFalse with empty operand stack");
+                    let cond = force(cond, stack);
+                    if cond == Closure::atomic_closure(Term::Bool(false).into()) {
+                        ip = read_u32(&self.code, operand_start) as usize;
+                    }
+                }
+                Op::Return => {
+                    return Ok(operands.pop().expect("Return with empty operand stack"));
+                }
+            }
+        }
+    }
+}
+
+/// Recover the [Op] a raw opcode byte was written from.
+///
+/// This mirrors `Op`'s `#[repr(u8)]` discriminants; it exists because `code` stores opcodes as
+/// plain bytes; matching here keeps that cast in one place instead of scattered at every call
+/// site that walks a chunk's instructions. Returns `None` for a byte that isn't one of `Op`'s
+/// discriminants rather than panicking: [Chunk::decode_code] feeds this bytes read straight off
+/// disk via [crate::cache], and a corrupted cache file should be a reported miss, not a crash.
+fn decode_op(byte: u8) -> Option<Op> {
+    Some(match byte {
+        b if b == Op::PushConst as u8 => Op::PushConst,
+        b if b == Op::Apply as u8 => Op::Apply,
+        b if b == Op::UpdateThunk as u8 => Op::UpdateThunk,
+        b if b == Op::Op1 as u8 => Op::Op1,
+        b if b == Op::Op2 as u8 => Op::Op2,
+        b if b == Op::TestEq as u8 => Op::TestEq,
+        b if b == Op::PopEqs as u8 => Op::PopEqs,
+        b if b == Op::Jump as u8 => Op::Jump,
+        b if b == Op::JumpIfFalse as u8 => Op::JumpIfFalse,
+        b if b == Op::Return as u8 => Op::Return,
+        _ => return None,
+    })
+}
+
+/// Decode a little-endian `u16` operand starting at `code[at]`.
+fn read_u16(code: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([code[at], code[at + 1]])
+}
+
+/// Decode a little-endian `u32` operand starting at `code[at]`.
+fn read_u32(code: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([code[at], code[at + 1], code[at + 2], code[at + 3]])
+}
+
+/// Lowers a [Term] into a [Chunk] ahead of evaluation.
+///
+/// The compiler walks the term tree once, emitting one or more [Op]s per node, so [Chunk::run]'s
+/// hot loop only ever decodes bytes and jumps; it never pattern-matches an AST. Only term shapes
+/// with a direct, environment-free bytecode encoding are lowered this way; everything else is
+/// pushed as a constant to be forced later, the same way the rest of the lazy evaluator already
+/// defers work (see the module documentation).
+#[derive(Debug, Default)]
+pub struct Compiler {
+    chunk: Chunk,
+    /// The span to record against every `Apply` this compilation emits.
+    ///
+    /// `Term` carries no position of its own - spans live alongside it as a sibling value
+    /// everywhere else in this codebase (see `Marker::Arg`/`Marker::Cont` in [crate::stack]) -
+    /// so a single term handed to [Compiler::compile] only has the one span its caller already
+    /// has for it, not a distinct span per `App` node nested inside. Every `Apply` this chunk
+    /// compiles is stamped with that same span; callers that need per-call-site spans for a term
+    /// with several nested applications will need those tracked further upstream, before the
+    /// term ever reaches the compiler.
+    pos: Option<RawSpan>,
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Compiler {
+            chunk: Chunk::new(),
+            pos: None,
+        }
+    }
+
+    /// Compile `term`, consuming the compiler and returning the resulting [Chunk]. `pos` is the
+    /// span `term` occurs at, if known, recorded against every `Apply` the compiled chunk runs so
+    /// a recursion limit hit partway through has somewhere to point.
+    pub fn compile(mut self, term: &Term, pos: Option<RawSpan>) -> Chunk {
+        self.pos = pos;
+        self.compile_term(term);
+        self.chunk.push_op(Op::UpdateThunk);
+        self.chunk.push_op(Op::Return);
+        self.chunk
+    }
+
+    /// Compile `term` as an opaque thunk: always a `PushConst` of `term` itself, regardless of
+    /// whether `term`'s shape also has a direct bytecode encoding.
+    ///
+    /// This is [Compiler::compile_term]'s catch-all fallback, reused here on purpose: an `App`
+    /// argument must stay a thunk forced on demand, not bytecode run eagerly, so it always goes
+    /// through this path rather than [Compiler::compile_term]'s direct-encoding arms (see the
+    /// module documentation).
+    fn compile_thunked(&mut self, term: &Term) {
+        let idx = self.chunk.add_constant(term.clone());
+        self.chunk.push_op(Op::PushConst);
+        self.chunk.push_u16(idx);
+    }
+
+    fn compile_term(&mut self, term: &Term) {
+        match term {
+            Term::App(f, arg) => {
+                self.compile_thunked(arg);
+                self.compile_term(f);
+                let apply_at = self.chunk.push_op(Op::Apply);
+                self.chunk.push_u16(1);
+                if let Some(pos) = self.pos.clone() {
+                    self.chunk.spans.push((apply_at, pos));
+                }
+            }
+            Term::Op1(op, t) => {
+                self.compile_term(t);
+                let idx = self.chunk.add_unary_op(op.clone());
+                self.chunk.push_op(Op::Op1);
+                self.chunk.push_u16(idx);
+            }
+            // Structural equality is driven by `TestEq`/`PopEqs` rather than the generic `Op2`
+            // dispatch: the abstract machine has always treated it specially (see `Marker::Eq` in
+            // `crate::stack`), since a failed comparison needs to discard whatever other queued
+            // equalities an enclosing composite comparison left on `Stack`. `PopEqs` is emitted
+            // unconditionally rather than only on the failing branch, since discarding an empty
+            // queue is a no-op.
+            Term::Op2(op, t1, t2) if matches!(*op, BinaryOp::Eq()) => {
+                self.compile_term(t1);
+                self.compile_term(t2);
+                self.chunk.push_op(Op::TestEq);
+                self.chunk.push_op(Op::PopEqs);
+            }
+            Term::Op2(op, t1, t2) => {
+                self.compile_term(t1);
+                self.compile_term(t2);
+                let idx = self.chunk.add_binary_op(op.clone());
+                self.chunk.push_op(Op::Op2);
+                self.chunk.push_u16(idx);
+            }
+            Term::If(cond, t, e) => {
+                self.compile_term(cond);
+                let jump_if_false = self.chunk.push_op(Op::JumpIfFalse);
+                self.chunk.push_u32(0);
+                self.compile_term(t);
+                let jump_over_else = self.chunk.push_op(Op::Jump);
+                self.chunk.push_u32(0);
+                let else_target = self.chunk.code.len() as u32;
+                self.chunk.patch_jump(jump_if_false + 1, else_target);
+                self.compile_term(e);
+                let end_target = self.chunk.code.len() as u32;
+                self.chunk.patch_jump(jump_over_else + 1, end_target);
+            }
+            // `Var`, `Let`, `Fun`, records and anything else here needs an environment to make
+            // sense of - there's nowhere in a flat `code` stream to put one yet - so the term is
+            // kept around whole, to be forced by the existing evaluator on demand, exactly as it
+            // would be without a compiler in the loop at all.
+            other => self.compile_thunked(other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::term::Term;
+
+    #[test]
+    fn compiles_a_leaf_term_to_a_push_update_thunk_and_return() {
+        let chunk = Compiler::new().compile(&Term::Bool(true), None);
+        assert_eq!(chunk.constants.len(), 1);
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::PushConst as u8,
+                0,
+                0,
+                Op::UpdateThunk as u8,
+                Op::Return as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn compiling_an_equality_emits_test_eq_and_pop_eqs() {
+        let chunk = Compiler::new().compile(
+            &Term::Op2(
+                BinaryOp::Eq(),
+                Box::new(Term::Bool(true)),
+                Box::new(Term::Bool(false)),
+            ),
+            None,
+        );
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::PushConst as u8,
+                0,
+                0,
+                Op::PushConst as u8,
+                1,
+                0,
+                Op::TestEq as u8,
+                Op::PopEqs as u8,
+                Op::UpdateThunk as u8,
+                Op::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn patching_a_jump_rewrites_only_its_operand_bytes() {
+        let mut chunk = Chunk::new();
+        let jump = chunk.push_op(Op::Jump);
+        chunk.push_u32(0);
+        chunk.patch_jump(jump + 1, 42);
+        assert_eq!(&chunk.code[jump + 1..jump + 5], &42u32.to_le_bytes());
+    }
+
+    #[test]
+    fn varint_encoding_a_chunk_round_trips_to_the_same_code() {
+        let chunk = Compiler::new().compile(
+            &Term::If(
+                Box::new(Term::Bool(true)),
+                Box::new(Term::Bool(false)),
+                Box::new(Term::Bool(true)),
+            ),
+            None,
+        );
+        let encoded = chunk.encode_code();
+        assert!(encoded.len() <= chunk.code.len());
+        assert_eq!(Some(chunk.code.clone()), Chunk::decode_code(&encoded));
+    }
+
+    /// `run` is driven entirely through injected `force`/`apply_unary`/`apply_binary` hooks, so a
+    /// simple passthrough that never actually forces anything is enough to exercise the
+    /// instruction pointer's dispatch, jumps and operand-stack bookkeeping on their own.
+    fn passthrough(closure: Closure, _stack: &mut Stack) -> Closure {
+        closure
+    }
+
+    #[test]
+    fn running_a_leaf_term_yields_its_constant() {
+        let chunk = Compiler::new().compile(&Term::Bool(true), None);
+        let mut stack = Stack::new();
+        let result = chunk
+            .run(&mut stack, passthrough, |_, c, _| c, |_, l, _, _| l)
+            .expect("no recursion limit configured");
+        assert_eq!(result, Closure::atomic_closure(Term::Bool(true).into()));
+    }
+
+    #[test]
+    fn running_an_if_takes_the_matching_branch() {
+        // The condition is `false`, so the chunk should produce the `else` branch's constant
+        // rather than the `then` branch's.
+        let chunk = Compiler::new().compile(
+            &Term::If(
+                Box::new(Term::Bool(false)),
+                Box::new(Term::Bool(true)),
+                Box::new(Term::Bool(false)),
+            ),
+            None,
+        );
+        let mut stack = Stack::new();
+        let result = chunk
+            .run(&mut stack, passthrough, |_, c, _| c, |_, l, _, _| l)
+            .expect("no recursion limit configured");
+        assert_eq!(result, Closure::atomic_closure(Term::Bool(false).into()));
+    }
+
+    #[test]
+    fn running_an_equality_test_eq_and_clears_queued_eqs() {
+        let chunk = Compiler::new().compile(
+            &Term::Op2(
+                BinaryOp::Eq(),
+                Box::new(Term::Bool(true)),
+                Box::new(Term::Bool(true)),
+            ),
+            None,
+        );
+        let mut stack = Stack::new();
+        stack.push_eqs(std::iter::once((
+            Closure::atomic_closure(Term::Bool(true).into()),
+            Closure::atomic_closure(Term::Bool(true).into()),
+        )));
+        let result = chunk
+            .run(&mut stack, passthrough, |_, c, _| c, |_, l, _, _| l)
+            .expect("no recursion limit configured");
+        assert_eq!(result, Closure::atomic_closure(Term::Bool(true).into()));
+        assert!(stack.pop_eq().is_none());
+    }
+
+    #[test]
+    fn applying_a_function_that_ignores_its_argument_does_not_force_it() {
+        // `Op1(IsNum, ..)` has a direct bytecode encoding; if `App`'s argument were compiled the
+        // same way a top-level term is, this argument would run through `Op1` - and the
+        // `apply_unary` hook below - before `Apply` even executes. The function this applies to
+        // discards its argument instead of using it, so if the argument were forced regardless
+        // of that, this test fails via the panicking hook rather than a wrong answer.
+        let poisoned_arg = Term::Op1(UnaryOp::IsNum(), Box::new(Term::Bool(true)));
+        let app = Term::App(Box::new(Term::Bool(true)), Box::new(poisoned_arg));
+        let chunk = Compiler::new().compile(&app, None);
+
+        let mut stack = Stack::new();
+        let result = chunk
+            .run(
+                &mut stack,
+                |closure, stack| {
+                    // A function that discards its argument instead of forcing it.
+                    stack.pop_arg();
+                    closure
+                },
+                |_, _, _| panic!("the ignored argument must not be forced"),
+                |_, l, _, _| l,
+            )
+            .expect("no recursion limit configured");
+        assert_eq!(result, Closure::atomic_closure(Term::Bool(true).into()));
+    }
+
+    #[test]
+    fn an_unrecognized_opcode_byte_is_a_decode_miss_rather_than_a_panic() {
+        assert_eq!(Chunk::decode_code(&[0xff]), None);
+    }
+}
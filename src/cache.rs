@@ -0,0 +1,417 @@
+//! Persist compiled [Chunk]s to an on-disk cache keyed by a hash of their source, so re-running
+//! an unchanged file skips parsing and compilation entirely.
+//!
+//! This module owns the integer-heavy framing of the cache file format - the format version, the
+//! source hash, and the [Chunk]'s `code` stream, all [varint]-encoded - the filesystem I/O and
+//! hash-validation logic that turns that framing into an actual cache, and (de)serialization of
+//! the `constants`/`unary_ops`/`binary_ops` pools that follow the header: [store] writes a
+//! complete entry to `cache_dir`, and [load] hands back a [Chunk] ready to run straight away, only
+//! on a genuine cache hit.
+//!
+//! [store]/[load] only know how to encode the handful of `Term`/`UnaryOp`/`BinaryOp` shapes
+//! [encode_term]/[encode_unary_op]/[encode_binary_op] recognize - the same direct-encoding shapes
+//! [crate::bytecode::Compiler] lowers to real opcodes, plus the literals and opaque subterms that
+//! end up in the constant pool. A chunk built from a shape outside that list (most of the
+//! `Var`/`Let`/`Fun`/record terms a real program is actually made of) simply isn't written to the
+//! cache at all - [store] reports that honestly by returning `Ok(false)` rather than writing a
+//! file [load] couldn't fully reconstruct. This module does not persist `Chunk::spans` either:
+//! `RawSpan`'s own layout isn't something this module has a byte encoding for, so a [load]ed
+//! chunk always comes back with an empty `spans` - it runs correctly, but a recursion-limit error
+//! hit through a cache hit won't carry the call-site span a fresh compile would have had.
+//!
+//! It also does not persist anything about `Marker::Cont`'s `usize` call-stack size: that's
+//! per-evaluation runtime state, not part of the compiled program a source file hashes to, so
+//! there's nothing meaningful to cache it against.
+use crate::bytecode::Chunk;
+use crate::term::{BinaryOp, Term, UnaryOp};
+use crate::varint;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Bumped whenever the cache file layout changes, so a cache written by an older version is
+/// rejected outright instead of being misdecoded.
+const CACHE_FORMAT_VERSION: u8 = 1;
+
+/// A fast, non-cryptographic hash of a chunk's source text (FNV-1a).
+///
+/// This is good enough to detect that a source file changed since it was cached; it is not a
+/// defense against a deliberately corrupted cache file.
+pub fn hash_source(source: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in source {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// The header of a cache file: everything [decode] can reconstruct without help, plus the sizes
+/// of the constant and operator pools that follow it in the file. `spans_len` records how many
+/// spans the chunk had; the spans themselves are not written.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Header {
+    pub source_hash: u64,
+    pub code: Vec<u8>,
+    pub constants_len: usize,
+    pub unary_ops_len: usize,
+    pub binary_ops_len: usize,
+    pub spans_len: usize,
+}
+
+/// Write `chunk`'s header - source hash and `code` stream - to a cache file body.
+///
+/// The caller is expected to append the encoded constant and operator pools after the bytes
+/// returned here; see [store] for a caller that does this and writes a complete entry.
+pub fn encode(chunk: &Chunk, source_hash: u64) -> Vec<u8> {
+    let mut out = vec![CACHE_FORMAT_VERSION];
+    varint::write(&mut out, source_hash);
+    let code = chunk.encode_code();
+    varint::write_usize(&mut out, code.len());
+    out.extend_from_slice(&code);
+    varint::write_usize(&mut out, chunk.constants.len());
+    varint::write_usize(&mut out, chunk.unary_ops.len());
+    varint::write_usize(&mut out, chunk.binary_ops.len());
+    varint::write_usize(&mut out, chunk.spans.len());
+    out
+}
+
+/// Decode a cache file's header, returning it along with the unconsumed tail of `input` (the
+/// encoded constant and operator pools).
+///
+/// Returns `None` if `input` doesn't start with a header this version of the format produced, or
+/// if the header is truncated.
+pub fn decode(input: &[u8]) -> Option<(Header, &[u8])> {
+    let (&version, mut cursor) = input.split_first()?;
+    if version != CACHE_FORMAT_VERSION {
+        return None;
+    }
+    let source_hash = varint::read(&mut cursor)?;
+    let code_len = varint::read_usize(&mut cursor)?;
+    if cursor.len() < code_len {
+        return None;
+    }
+    let (encoded_code, rest) = cursor.split_at(code_len);
+    let code = Chunk::decode_code(encoded_code)?;
+    cursor = rest;
+    let constants_len = varint::read_usize(&mut cursor)?;
+    let unary_ops_len = varint::read_usize(&mut cursor)?;
+    let binary_ops_len = varint::read_usize(&mut cursor)?;
+    let spans_len = varint::read_usize(&mut cursor)?;
+    Some((
+        Header {
+            source_hash,
+            code,
+            constants_len,
+            unary_ops_len,
+            binary_ops_len,
+            spans_len,
+        },
+        cursor,
+    ))
+}
+
+/// The path a cache entry for the source file at `source_path` would live at under `cache_dir`.
+///
+/// Entries are keyed by the source path (hashed, to keep file names fixed-width and avoid
+/// reproducing the path's own separators) rather than by the content hash, so repeated runs
+/// against the same file land on the same entry; [load] is what catches the file's contents
+/// having changed since that entry was written.
+pub fn cache_path(cache_dir: &Path, source_path: &Path) -> PathBuf {
+    let path_hash = hash_source(source_path.to_string_lossy().as_bytes());
+    cache_dir.join(format!("{path_hash:016x}.nickelc"))
+}
+
+/// Encode a single [Term] into `out`, recursing into the handful of shapes this module
+/// recognizes. Returns `false` - leaving `out` in an unspecified but harmless state - if `term`
+/// contains a shape outside that list, so the caller can fall back to not caching the whole
+/// chunk rather than writing bytes nothing could decode back.
+fn encode_term(term: &Term, out: &mut Vec<u8>) -> bool {
+    match term {
+        Term::Bool(b) => {
+            out.push(0);
+            out.push(*b as u8);
+            true
+        }
+        Term::If(cond, t, e) => {
+            out.push(1);
+            encode_term(cond, out) && encode_term(t, out) && encode_term(e, out)
+        }
+        Term::App(f, arg) => {
+            out.push(2);
+            encode_term(f, out) && encode_term(arg, out)
+        }
+        Term::Op1(op, t) => {
+            if !matches!(op, UnaryOp::IsNum()) {
+                return false;
+            }
+            out.push(3);
+            encode_term(t, out)
+        }
+        Term::Op2(op, t1, t2) => {
+            if !matches!(op, BinaryOp::Eq()) {
+                return false;
+            }
+            out.push(4);
+            encode_term(t1, out) && encode_term(t2, out)
+        }
+        _ => false,
+    }
+}
+
+/// Inverse of [encode_term]. Returns `None` if `input` is exhausted or holds a tag this version
+/// of the format doesn't recognize.
+fn decode_term(input: &mut &[u8]) -> Option<Term> {
+    let (&tag, rest) = input.split_first()?;
+    *input = rest;
+    match tag {
+        0 => {
+            let (&b, rest) = input.split_first()?;
+            *input = rest;
+            Some(Term::Bool(b != 0))
+        }
+        1 => {
+            let cond = decode_term(input)?;
+            let t = decode_term(input)?;
+            let e = decode_term(input)?;
+            Some(Term::If(Box::new(cond), Box::new(t), Box::new(e)))
+        }
+        2 => {
+            let f = decode_term(input)?;
+            let arg = decode_term(input)?;
+            Some(Term::App(Box::new(f), Box::new(arg)))
+        }
+        3 => Some(Term::Op1(UnaryOp::IsNum(), Box::new(decode_term(input)?))),
+        4 => {
+            let t1 = decode_term(input)?;
+            let t2 = decode_term(input)?;
+            Some(Term::Op2(BinaryOp::Eq(), Box::new(t1), Box::new(t2)))
+        }
+        _ => None,
+    }
+}
+
+/// Encode a single [UnaryOp]. Returns `false` for any variant besides `IsNum`, the only one this
+/// module currently recognizes.
+fn encode_unary_op(op: &UnaryOp, out: &mut Vec<u8>) -> bool {
+    if matches!(op, UnaryOp::IsNum()) {
+        out.push(0);
+        true
+    } else {
+        false
+    }
+}
+
+fn decode_unary_op(input: &mut &[u8]) -> Option<UnaryOp> {
+    let (&tag, rest) = input.split_first()?;
+    *input = rest;
+    match tag {
+        0 => Some(UnaryOp::IsNum()),
+        _ => None,
+    }
+}
+
+/// Encode a single [BinaryOp]. Returns `false` for any variant besides `Eq`, the only one this
+/// module currently recognizes.
+fn encode_binary_op(op: &BinaryOp, out: &mut Vec<u8>) -> bool {
+    if matches!(op, BinaryOp::Eq()) {
+        out.push(0);
+        true
+    } else {
+        false
+    }
+}
+
+fn decode_binary_op(input: &mut &[u8]) -> Option<BinaryOp> {
+    let (&tag, rest) = input.split_first()?;
+    *input = rest;
+    match tag {
+        0 => Some(BinaryOp::Eq()),
+        _ => None,
+    }
+}
+
+/// Encode `chunk`'s constant, unary- and binary-operator pools, in that order. Returns `None` if
+/// any entry in any of the three pools is outside what [encode_term]/[encode_unary_op]/
+/// [encode_binary_op] recognize.
+fn encode_pools(chunk: &Chunk) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    for term in &chunk.constants {
+        if !encode_term(term, &mut out) {
+            return None;
+        }
+    }
+    for op in &chunk.unary_ops {
+        if !encode_unary_op(op, &mut out) {
+            return None;
+        }
+    }
+    for op in &chunk.binary_ops {
+        if !encode_binary_op(op, &mut out) {
+            return None;
+        }
+    }
+    Some(out)
+}
+
+/// Inverse of [encode_pools]: decode exactly `constants_len` constants, `unary_ops_len` unary
+/// operators and `binary_ops_len` binary operators from the front of `input`.
+fn decode_pools(
+    mut input: &[u8],
+    constants_len: usize,
+    unary_ops_len: usize,
+    binary_ops_len: usize,
+) -> Option<(Vec<Term>, Vec<UnaryOp>, Vec<BinaryOp>)> {
+    let constants = (0..constants_len)
+        .map(|_| decode_term(&mut input))
+        .collect::<Option<Vec<_>>>()?;
+    let unary_ops = (0..unary_ops_len)
+        .map(|_| decode_unary_op(&mut input))
+        .collect::<Option<Vec<_>>>()?;
+    let binary_ops = (0..binary_ops_len)
+        .map(|_| decode_binary_op(&mut input))
+        .collect::<Option<Vec<_>>>()?;
+    Some((constants, unary_ops, binary_ops))
+}
+
+/// Write a complete cache entry for `chunk` to the entry for `source_path` under `cache_dir`,
+/// creating the directory first if it doesn't exist yet.
+///
+/// Returns `Ok(true)` once the entry is written, or `Ok(false)` if `chunk` contains a `Term`,
+/// `UnaryOp` or `BinaryOp` this module doesn't know how to encode (see the module
+/// documentation) - a deliberate, explicit "don't cache this one", not a write of a file [load]
+/// couldn't use. Skipping the write is always safe: the source is simply recompiled from scratch
+/// next time, exactly as if there were no cache entry at all.
+pub fn store(
+    cache_dir: &Path,
+    source_path: &Path,
+    chunk: &Chunk,
+    source_hash: u64,
+) -> io::Result<bool> {
+    let Some(pools) = encode_pools(chunk) else {
+        return Ok(false);
+    };
+    fs::create_dir_all(cache_dir)?;
+    let mut bytes = encode(chunk, source_hash);
+    bytes.extend_from_slice(&pools);
+    fs::write(cache_path(cache_dir, source_path), bytes)?;
+    Ok(true)
+}
+
+/// Read back the cache entry for `source_path` under `cache_dir`, as a [Chunk] ready to hand
+/// straight to the evaluator, only on a genuine cache hit: an entry exists, its header and pools
+/// are well-formed, and its embedded source hash still matches a freshly computed hash of
+/// `source`.
+///
+/// Returns `None` on any kind of miss: no entry, a truncated, wrong-version or undecodable entry,
+/// or a stale entry left behind by a since-edited source file. The returned chunk's `spans` is
+/// always empty (see the module documentation).
+pub fn load(cache_dir: &Path, source_path: &Path, source: &[u8]) -> Option<Chunk> {
+    let bytes = fs::read(cache_path(cache_dir, source_path)).ok()?;
+    let (header, rest) = decode(&bytes)?;
+    if header.source_hash != hash_source(source) {
+        return None;
+    }
+    let (constants, unary_ops, binary_ops) = decode_pools(
+        rest,
+        header.constants_len,
+        header.unary_ops_len,
+        header.binary_ops_len,
+    )?;
+    Some(Chunk {
+        code: header.code,
+        constants,
+        unary_ops,
+        binary_ops,
+        spans: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Compiler;
+    use crate::term::Term;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn header_round_trips_through_encode_and_decode() {
+        let chunk = Compiler::new().compile(&Term::Bool(true), None);
+        let source_hash = hash_source(b"true");
+        let bytes = encode(&chunk, source_hash);
+        let (header, rest) = decode(&bytes).expect("well-formed header");
+        assert_eq!(header.source_hash, source_hash);
+        assert_eq!(header.code, chunk.code);
+        assert_eq!(header.constants_len, chunk.constants.len());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn a_cache_from_a_newer_format_version_is_rejected() {
+        let mut bytes = encode(&Compiler::new().compile(&Term::Bool(true), None), 0);
+        bytes[0] = CACHE_FORMAT_VERSION + 1;
+        assert!(decode(&bytes).is_none());
+    }
+
+    #[test]
+    fn hashing_identical_sources_agrees_and_differing_sources_disagree() {
+        assert_eq!(hash_source(b"foo"), hash_source(b"foo"));
+        assert_ne!(hash_source(b"foo"), hash_source(b"bar"));
+    }
+
+    /// A fresh, unshared scratch directory per test, so parallel test runs don't trip over each
+    /// other's cache entries.
+    fn scratch_dir() -> PathBuf {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let dir =
+            std::env::temp_dir().join(format!("nickel-cache-test-{}-{id}", std::process::id()));
+        dir
+    }
+
+    #[test]
+    fn a_stored_entry_loads_back_as_a_runnable_chunk() {
+        let cache_dir = scratch_dir();
+        let source_path = Path::new("fixture.ncl");
+        let source = b"if true then (true == false) else (isNum true)";
+        let chunk = Compiler::new().compile(
+            &Term::If(
+                Box::new(Term::Bool(true)),
+                Box::new(Term::Op2(
+                    BinaryOp::Eq(),
+                    Box::new(Term::Bool(true)),
+                    Box::new(Term::Bool(false)),
+                )),
+                Box::new(Term::Op1(UnaryOp::IsNum(), Box::new(Term::Bool(true)))),
+            ),
+            None,
+        );
+
+        assert!(store(&cache_dir, source_path, &chunk, hash_source(source)).expect("can write"));
+        let loaded = load(&cache_dir, source_path, source).expect("entry was just written");
+        assert_eq!(loaded.code, chunk.code);
+        assert_eq!(loaded.constants, chunk.constants);
+        assert_eq!(loaded.unary_ops, chunk.unary_ops);
+        assert_eq!(loaded.binary_ops, chunk.binary_ops);
+
+        fs::remove_dir_all(&cache_dir).ok();
+    }
+
+    #[test]
+    fn an_entry_is_a_miss_once_the_source_it_was_written_for_changes() {
+        let cache_dir = scratch_dir();
+        let source_path = Path::new("fixture.ncl");
+        let chunk = Compiler::new().compile(&Term::Bool(true), None);
+
+        store(&cache_dir, source_path, &chunk, hash_source(b"true")).expect("can write");
+        assert!(load(&cache_dir, source_path, b"false").is_none());
+
+        fs::remove_dir_all(&cache_dir).ok();
+    }
+
+    #[test]
+    fn loading_a_never_written_entry_is_a_miss() {
+        let cache_dir = scratch_dir();
+        assert!(load(&cache_dir, Path::new("never-cached.ncl"), b"true").is_none());
+    }
+}
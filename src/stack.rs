@@ -1,6 +1,7 @@
 //! Define the main evaluation stack of the Nickel abstract machine and related operations.
 //!
-//! See [eval](../eval/index.html).
+//! See [eval](../eval/index.html). For the compiled instruction stream the bytecode
+//! interpreter walks to drive this stack, see [crate::bytecode].
 use crate::eval::Closure;
 use crate::operation::OperationCont;
 use crate::position::RawSpan;
@@ -8,6 +9,10 @@ use std::cell::RefCell;
 use std::rc::Weak;
 
 /// An element of the stack.
+///
+/// The payload is kept as small as the `Arg`/`Thunk`/`Cont` markers - the ones on the hot
+/// push/pop path - can be: `Eq` is the only variant wide enough to need two `Closure`s, so it is
+/// boxed to keep `size_of::<Marker>()` from being dictated by the least common case.
 #[derive(Debug)]
 pub enum Marker {
     /// An equality to test.
@@ -17,7 +22,7 @@ pub enum Marker {
     /// first equality is evaluated and the remaining ones - the continuation of the whole
     /// computation - are put on the stack as `Eq` elements. If an equality evaluates to `false` at
     /// some point, all the consecutive `Eq` elements at the top of the stack are discarded.
-    Eq(Closure, Closure),
+    Eq(Box<(Closure, Closure)>),
     /// An argument of an application.
     Arg(Closure, Option<RawSpan>),
     /// A thunk, which is pointer to a mutable memory cell to be updated.
@@ -30,85 +35,139 @@ pub enum Marker {
     ),
 }
 
-impl Marker {
-    pub fn is_arg(&self) -> bool {
-        match *self {
-            Marker::Arg(_, _) => true,
-            _ => false,
-        }
-    }
-
-    pub fn is_thunk(&self) -> bool {
-        match *self {
-            Marker::Thunk(_) => true,
-            _ => false,
-        }
-    }
-
-    pub fn is_cont(&self) -> bool {
-        match *self {
-            Marker::Cont(_, _, _) => true,
-            _ => false,
-        }
-    }
+/// The default maximum number of live call/continuation frames a [Stack] tracks before it starts
+/// rejecting new ones with [RecursionLimitExceeded], if the embedder doesn't configure one of
+/// their own via [Stack::with_max_frames]. Generous enough not to get in the way of any
+/// reasonable Nickel program, but finite, so a runaway recursive expression fails fast with a
+/// span instead of growing the process to an out-of-memory kill.
+pub const DEFAULT_MAX_FRAMES: usize = 1_000_000;
+
+/// Error raised when pushing a frame would grow the evaluation stack past its configured limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecursionLimitExceeded {
+    /// The span of the operation that tripped the limit, if known.
+    pub span: Option<RawSpan>,
+}
 
-    pub fn is_eq(&self) -> bool {
-        match *self {
-            Marker::Eq(..) => true,
-            _ => false,
-        }
-    }
+/// A one-byte discriminant, kept in lock-step with [Stack]'s marker payloads in a parallel
+/// stack, so the kind of the top element can be read without touching (or even loading) its
+/// payload.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tag {
+    Eq,
+    Arg,
+    Thunk,
+    Cont,
 }
 
 /// The evaluation stack.
+///
+/// Alongside the marker payloads, `Stack` keeps a parallel stack of one-byte [Tag]s. Checking
+/// what kind of element is on top - `is_top_thunk`, `is_top_cont`, `count_args`, `clear_eqs` - or
+/// popping a specific kind only ever reads `tags`; there is no speculative pop of `markers`
+/// followed by a push-back on a kind mismatch.
+///
+/// Only [Marker::Arg] and [Marker::Cont] elements count against `max_frames`: they are the ones
+/// that correspond to a live call or primitive-operation continuation. Thunks and pending
+/// equalities don't represent unbounded recursion on their own and are left uncounted.
 #[derive(Debug)]
-pub struct Stack(Vec<Marker>);
+pub struct Stack {
+    markers: Vec<Marker>,
+    tags: Vec<Tag>,
+    frame_count: usize,
+    max_frames: usize,
+}
 
 impl IntoIterator for Stack {
     type Item = Marker;
     type IntoIter = ::std::vec::IntoIter<Marker>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.0.into_iter()
+        self.markers.into_iter()
     }
 }
 
 impl Stack {
     pub fn new() -> Stack {
-        Stack(Vec::new())
+        Stack::with_max_frames(DEFAULT_MAX_FRAMES)
     }
 
-    /// Count the number of consecutive elements satisfying `pred` from the top of the stack.
-    fn count<P>(&self, pred: P) -> usize
-    where
-        P: Fn(&Marker) -> bool,
-    {
-        let mut count = 0;
-        for marker in self.0.iter().rev() {
-            if pred(marker) {
-                count += 1;
-            } else {
-                break;
-            }
+    /// Create an evaluation stack with a custom frame limit, for embedders that want to raise or
+    /// lower [DEFAULT_MAX_FRAMES].
+    pub fn with_max_frames(max_frames: usize) -> Stack {
+        Stack {
+            markers: Vec::new(),
+            tags: Vec::new(),
+            frame_count: 0,
+            max_frames,
+        }
+    }
+
+    /// Push `marker`, tagging it with `tag`.
+    fn push(&mut self, marker: Marker, tag: Tag) {
+        self.tags.push(tag);
+        self.markers.push(marker);
+    }
+
+    /// Pop the top marker if it is tagged `tag`, returning its payload. If `None` is returned,
+    /// the top element wasn't tagged `tag` and the stack is left untouched.
+    fn pop(&mut self, tag: Tag) -> Option<Marker> {
+        if self.tags.last() != Some(&tag) {
+            return None;
         }
-        count
+        self.tags.pop();
+        let marker = self
+            .markers
+            .pop()
+            .unwrap_or_else(|| unreachable!("tag stack and marker stack diverged"));
+        Some(marker)
+    }
+
+    /// Count the number of consecutive elements tagged `tag` from the top of the stack, reading
+    /// only the tag stack.
+    fn count(&self, tag: Tag) -> usize {
+        self.tags.iter().rev().take_while(|&&t| t == tag).count()
     }
 
     /// Count the number of arguments at the top of the stack.
     pub fn count_args(&self) -> usize {
-        Stack::count(self, Marker::is_arg)
+        self.count(Tag::Arg)
+    }
+
+    /// Push a frame, failing with [RecursionLimitExceeded] instead of growing past `max_frames`.
+    fn push_frame(&mut self, marker: Marker, tag: Tag) -> Result<(), RecursionLimitExceeded> {
+        if self.frame_count >= self.max_frames {
+            let span = match &marker {
+                Marker::Arg(_, pos) | Marker::Cont(_, _, pos) => *pos,
+                _ => None,
+            };
+            return Err(RecursionLimitExceeded { span });
+        }
+        self.frame_count += 1;
+        self.push(marker, tag);
+        Ok(())
     }
 
-    pub fn push_arg(&mut self, arg: Closure, pos: Option<RawSpan>) {
-        self.0.push(Marker::Arg(arg, pos))
+    pub fn push_arg(
+        &mut self,
+        arg: Closure,
+        pos: Option<RawSpan>,
+    ) -> Result<(), RecursionLimitExceeded> {
+        self.push_frame(Marker::Arg(arg, pos), Tag::Arg)
     }
 
     pub fn push_thunk(&mut self, thunk: Weak<RefCell<Closure>>) {
-        self.0.push(Marker::Thunk(thunk))
+        self.push(Marker::Thunk(thunk), Tag::Thunk)
     }
 
-    pub fn push_op_cont(&mut self, cont: OperationCont, len: usize, pos: Option<RawSpan>) {
-        self.0.push(Marker::Cont(cont, len, pos))
+    pub fn push_op_cont(
+        &mut self,
+        cont: OperationCont,
+        len: usize,
+        pos: Option<RawSpan>,
+    ) -> Result<(), RecursionLimitExceeded> {
+        self.push_frame(Marker::Cont(cont, len, pos), Tag::Cont)
     }
 
     /// Push a sequence of equalities on the stack.
@@ -116,69 +175,65 @@ impl Stack {
     where
         I: Iterator<Item = (Closure, Closure)>,
     {
-        self.0.extend(it.map(|(t1, t2)| Marker::Eq(t1, t2)));
+        for pair in it {
+            self.push(Marker::Eq(Box::new(pair)), Tag::Eq);
+        }
     }
 
     /// Try to pop an argument from the top of the stack. If `None` is returned, the top element
     /// was not an argument and the stack is left unchanged.
     pub fn pop_arg(&mut self) -> Option<(Closure, Option<RawSpan>)> {
-        match self.0.pop() {
-            Some(Marker::Arg(arg, pos)) => Some((arg, pos)),
-            Some(m) => {
-                self.0.push(m);
-                None
+        match self.pop(Tag::Arg) {
+            Some(Marker::Arg(arg, pos)) => {
+                self.frame_count -= 1;
+                Some((arg, pos))
             }
-            _ => None,
+            Some(_) => unreachable!("tag stack and marker stack diverged"),
+            None => None,
         }
     }
 
     /// Try to pop a thunk from the top of the stack. If `None` is returned, the top element was
     /// not a thunk and the stack is left unchanged.
     pub fn pop_thunk(&mut self) -> Option<Weak<RefCell<Closure>>> {
-        match self.0.pop() {
+        match self.pop(Tag::Thunk) {
             Some(Marker::Thunk(thunk)) => Some(thunk),
-            Some(m) => {
-                self.0.push(m);
-                None
-            }
-            _ => None,
+            Some(_) => unreachable!("tag stack and marker stack diverged"),
+            None => None,
         }
     }
 
     /// Try to pop an operator continuation from the top of the stack. If `None` is returned, the
     /// top element was not an operator continuation and the stack is left unchanged.
     pub fn pop_op_cont(&mut self) -> Option<(OperationCont, usize, Option<RawSpan>)> {
-        match self.0.pop() {
-            Some(Marker::Cont(cont, len, pos)) => Some((cont, len, pos)),
-            Some(m) => {
-                self.0.push(m);
-                None
+        match self.pop(Tag::Cont) {
+            Some(Marker::Cont(cont, len, pos)) => {
+                self.frame_count -= 1;
+                Some((cont, len, pos))
             }
-            _ => None,
+            Some(_) => unreachable!("tag stack and marker stack diverged"),
+            None => None,
         }
     }
 
     /// Try to pop an equality from the top of the stack. If `None` is returned, the top element
     /// was not an equality and the stack is left unchanged.
     pub fn pop_eq(&mut self) -> Option<(Closure, Closure)> {
-        if self.0.last().map(Marker::is_eq).unwrap_or(false) {
-            match self.0.pop() {
-                Some(Marker::Eq(c1, c2)) => Some((c1, c2)),
-                _ => panic!(),
-            }
-        } else {
-            None
+        match self.pop(Tag::Eq) {
+            Some(Marker::Eq(pair)) => Some(*pair),
+            Some(_) => unreachable!("tag stack and marker stack diverged"),
+            None => None,
         }
     }
 
-    /// Check if the top element is an argument.
+    /// Check if the top element is a thunk.
     pub fn is_top_thunk(&self) -> bool {
-        self.0.last().map(Marker::is_thunk).unwrap_or(false)
+        self.tags.last() == Some(&Tag::Thunk)
     }
 
     /// Check if the top element is an operation continuation.
     pub fn is_top_cont(&self) -> bool {
-        self.0.last().map(Marker::is_cont).unwrap_or(false)
+        self.tags.last() == Some(&Tag::Cont)
     }
 
     /// Discard all the consecutive equality from the top of the stack. This drops the continuation
@@ -197,12 +252,12 @@ mod tests {
     impl Stack {
         /// Count the number of thunks at the top of the stack.
         pub fn count_thunks(&self) -> usize {
-            Stack::count(self, Marker::is_thunk)
+            self.count(Tag::Thunk)
         }
 
         /// Count the number of operation continuation at the top of the stack.
         pub fn count_conts(&self) -> usize {
-            Stack::count(self, Marker::is_cont)
+            self.count(Tag::Cont)
         }
     }
 
@@ -214,33 +269,13 @@ mod tests {
         OperationCont::Op1(UnaryOp::IsNum(), None)
     }
 
-    fn some_arg_marker() -> Marker {
-        Marker::Arg(some_closure(), None)
-    }
-
-    fn some_thunk_marker() -> Marker {
-        let rc = Rc::new(RefCell::new(some_closure()));
-        Marker::Thunk(Rc::downgrade(&rc))
-    }
-
-    fn some_cont_marker() -> Marker {
-        Marker::Cont(some_cont(), 42, None)
-    }
-
-    #[test]
-    fn marker_differentiates() {
-        assert!(some_arg_marker().is_arg());
-        assert!(some_thunk_marker().is_thunk());
-        assert!(some_cont_marker().is_cont());
-    }
-
     #[test]
     fn pushing_and_poping_args() {
         let mut s = Stack::new();
         assert_eq!(0, s.count_args());
 
-        s.push_arg(some_closure(), None);
-        s.push_arg(some_closure(), None);
+        s.push_arg(some_closure(), None).expect("under the limit");
+        s.push_arg(some_closure(), None).expect("under the limit");
         assert_eq!(2, s.count_args());
         assert_eq!(some_closure(), s.pop_arg().expect("Already checked").0);
         assert_eq!(1, s.count_args());
@@ -263,8 +298,10 @@ mod tests {
         let mut s = Stack::new();
         assert_eq!(0, s.count_conts());
 
-        s.push_op_cont(some_cont(), 3, None);
-        s.push_op_cont(some_cont(), 4, None);
+        s.push_op_cont(some_cont(), 3, None)
+            .expect("under the limit");
+        s.push_op_cont(some_cont(), 4, None)
+            .expect("under the limit");
         assert_eq!(2, s.count_conts());
         assert_eq!(
             (some_cont(), 4, None),
@@ -272,4 +309,37 @@ mod tests {
         );
         assert_eq!(1, s.count_conts());
     }
+
+    #[test]
+    fn exceeding_the_frame_limit_fails_instead_of_growing_unbounded() {
+        let mut s = Stack::with_max_frames(1);
+        s.push_arg(some_closure(), None).expect("under the limit");
+
+        let err = s
+            .push_arg(some_closure(), None)
+            .expect_err("stack is already at its limit");
+        assert_eq!(err.span, None);
+        assert_eq!(1, s.count_args());
+    }
+
+    #[test]
+    fn clearing_eqs_discards_consecutive_equalities_without_disturbing_the_rest() {
+        let mut s = Stack::new();
+        s.push_thunk(Rc::downgrade(&Rc::new(RefCell::new(some_closure()))));
+        s.push_eqs(
+            vec![
+                (some_closure(), some_closure()),
+                (some_closure(), some_closure()),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(
+            (some_closure(), some_closure()),
+            s.pop_eq().expect("just pushed")
+        );
+
+        s.clear_eqs();
+        assert!(s.pop_eq().is_none());
+        assert!(s.is_top_thunk());
+    }
 }
@@ -0,0 +1,94 @@
+//! A LEB128-style variable-length integer encoding.
+//!
+//! Each byte carries 7 payload bits plus a high continuation bit: if the bit is set, at least
+//! one more byte follows. Small values - by far the common case for the constant-pool indices,
+//! jump offsets and pool sizes [crate::cache] writes - cost a single byte; only large ones spill
+//! into more.
+use std::convert::TryFrom;
+
+/// Append the varint encoding of `value` to `out`.
+pub fn write(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Decode a varint from the front of `input`, advancing `input` past the bytes consumed.
+///
+/// Returns `None` if `input` runs out before a terminating byte (high bit unset) is found, or if
+/// the encoded value doesn't fit in a `u64`.
+pub fn read(input: &mut &[u8]) -> Option<u64> {
+    let mut value: u64 = 0;
+    for shift in (0..70).step_by(7) {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+        let payload = u64::from(byte & 0x7f);
+        if shift == 63 && payload > 1 {
+            return None;
+        }
+        value |= payload << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        if shift >= 63 {
+            return None;
+        }
+    }
+    None
+}
+
+/// Convenience wrapper around [write] for values that are naturally `usize`.
+pub fn write_usize(out: &mut Vec<u8>, value: usize) {
+    write(out, value as u64);
+}
+
+/// Convenience wrapper around [read] for values that are naturally `usize`.
+pub fn read_usize(input: &mut &[u8]) -> Option<usize> {
+    usize::try_from(read(input)?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: u64) {
+        let mut buf = Vec::new();
+        write(&mut buf, value);
+        let mut cursor = buf.as_slice();
+        assert_eq!(Some(value), read(&mut cursor));
+        assert!(cursor.is_empty());
+    }
+
+    #[test]
+    fn small_values_cost_a_single_byte() {
+        let mut buf = Vec::new();
+        write(&mut buf, 42);
+        assert_eq!(buf, vec![42]);
+    }
+
+    #[test]
+    fn values_at_the_7_bit_boundary_spill_into_a_second_byte() {
+        let mut buf = Vec::new();
+        write(&mut buf, 128);
+        assert_eq!(buf, vec![0x80, 0x01]);
+    }
+
+    #[test]
+    fn round_trips_a_range_of_values() {
+        for value in [0, 1, 42, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            round_trip(value);
+        }
+    }
+
+    #[test]
+    fn reading_past_the_end_of_a_truncated_stream_fails() {
+        let mut cursor: &[u8] = &[0x80, 0x80];
+        assert_eq!(None, read(&mut cursor));
+    }
+}